@@ -1,8 +1,8 @@
 // Copyright 2021 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
 use parsec_interface::operations::psa_algorithm::{
-    Aead, AeadWithDefaultLengthTag, Algorithm, AsymmetricSignature, Cipher, FullLengthMac, Hash,
-    KeyAgreement, Mac, RawKeyAgreement, SignHash,
+    Aead, AeadWithDefaultLengthTag, Algorithm, AsymmetricEncryption, AsymmetricSignature, Cipher,
+    FullLengthMac, Hash, KeyAgreement, KeyDerivationFunction, Mac, RawKeyAgreement, SignHash,
 };
 use parsec_interface::operations::psa_key_attributes::{Attributes, EccFamily, Type};
 use parsec_interface::requests::ResponseStatus;
@@ -81,12 +81,17 @@ impl AteccKeySlot {
             | Type::EccPublicKey {
                 curve_family: EccFamily::SecpR1,
             } => {
-                // There may be a problem here: P256 private key has 256 bits (32 bytes),
-                // but the uncompressed public key is 512 bits (64 bytes)
+                // The hardware point is 512 bits (64 bytes) uncompressed, but the
+                // reported attribute `bits` is the curve order, 256: see the `spki`
+                // module below for the DER encoding that carries the full point.
                 key_attr.bits == 256
                     && self.config.key_type == rust_cryptoauthlib::KeyType::P256EccKey
             }
-            Type::Derive | Type::DhKeyPair { .. } | Type::DhPublicKey { .. } => {
+            // Derived key material lands in a generic HMAC/SHA-capable slot, not a
+            // P256 one: the ECDH itself runs against the *other* slot holding the
+            // key pair (see the `WithKeyDerivation` arm in `is_permitted_algorithms_ok`).
+            Type::Derive => !self.config.no_mac,
+            Type::DhKeyPair { .. } | Type::DhPublicKey { .. } => {
                 // This may change...
                 false
             }
@@ -184,34 +189,461 @@ impl AteccKeySlot {
                 // RFC 6979
                 false
             }
-            // AsymmetricEncryption
-            Algorithm::AsymmetricEncryption(..) => {
-                // why only RSA? it could work with ECC...
-                false
-            }
-            // KeyAgreement
+            // AsymmetricEncryption: PSA only defines RSA identifiers here
+            // (`RsaPkcs1v15Crypt`, `RsaOaep`) — there is no ECC/ECIES algorithm
+            // identifier to match against, and the ATECC has no RSA engine, so
+            // both are rejected explicitly.
+            // The `ecies` module below is reached through `AteccKeySlot::encrypt`/
+            // `decrypt`, gated on the slot's ECDH capability flags directly instead
+            // of a permitted-algorithms identifier.
+            Algorithm::AsymmetricEncryption(AsymmetricEncryption::RsaPkcs1v15Crypt)
+            | Algorithm::AsymmetricEncryption(AsymmetricEncryption::RsaOaep { .. }) => false,
+            // KeyAgreement: raw ECDH, or ECDH followed by HKDF-SHA256 (see the
+            // `key_derivation` module below for the Extract/Expand computation). Only
+            // HKDF-SHA256 is implemented, so any other KDF falls through to `_ => false`.
             Algorithm::KeyAgreement(KeyAgreement::Raw(RawKeyAgreement::Ecdh))
             | Algorithm::KeyAgreement(KeyAgreement::WithKeyDerivation {
                 ka_alg: RawKeyAgreement::Ecdh,
-                ..
+                kdf_alg:
+                    KeyDerivationFunction::Hkdf {
+                        hash_alg: Hash::Sha256,
+                    },
             }) => self.config.key_type == rust_cryptoauthlib::KeyType::P256EccKey,
             // Nothing else is known to be supported by Atecc
             _ => false,
         }
     }
 
-    pub fn reference_check_and_set(&mut self) -> Result<(), ()> {
-        if 0 < self.ref_count {
-            Err(())
-        } else {
-            self.ref_count = 1;
-            Ok(())
+    /// Add a reference to this slot, for the first key placed in it or for a
+    /// later key that legitimately shares it (e.g. a read-only public key).
+    pub fn add_reference(&mut self) -> Result<(), ResponseStatus> {
+        self.ref_count = self
+            .ref_count
+            .checked_add(1)
+            .ok_or(ResponseStatus::PsaErrorInsufficientStorage)?;
+        Ok(())
+    }
+
+    /// Drop a reference to this slot, releasing it back to `Free` once no
+    /// key triple references it any longer. `Locked` slots are never
+    /// released, since the hardware itself has flagged them non-releasable.
+    pub fn release(&mut self) -> Result<(), ResponseStatus> {
+        self.ref_count = self.ref_count.saturating_sub(1);
+        if self.ref_count == 0 && self.status != KeySlotStatus::Locked {
+            self.status = KeySlotStatus::Free;
         }
+        Ok(())
     }
 
     pub fn is_free(&self) -> bool {
         matches!(self.status, KeySlotStatus::Free)
     }
+
+    /// Rank how permissive this slot's static hardware configuration is: one
+    /// point per enabled flag (private key, signing, ECDH, public-key
+    /// export/copy, secret, HMAC/SHA). For most key types compatibility
+    /// (`key_attr_vs_config`) already forces an exact `key_type` match, and
+    /// `key_type` is not scored at all; it's the *usage/capability* surface that varies
+    /// between e.g. an ECDH-only slot and one that also signs and exports.
+    /// Lower is more constrained, so the allocator prefers it and leaves the
+    /// more permissive (higher-scoring) slots free for future keys.
+    fn constraint_score(&self) -> u32 {
+        let attr = &self.config.ecc_key_attr;
+        [
+            attr.is_private,
+            attr.ext_sign,
+            attr.int_sign,
+            attr.ecdh_operation,
+            attr.ecdh_secret_out,
+            self.config.pub_info,
+            self.config.is_secret,
+            !self.config.no_mac,
+        ]
+        .iter()
+        .filter(|enabled| **enabled)
+        .count() as u32
+    }
+
+    /// PSA `psa_asymmetric_encrypt` entry point for this slot: ECIES-encrypt
+    /// `plaintext` to the P256 public key `recipient` (see the `ecies` module
+    /// below). Gated directly on the slot's ECDH capability flags, since PSA
+    /// has no algorithm identifier for this scheme (`is_permitted_algorithms_ok`
+    /// rejects every `AsymmetricEncryption` variant for that reason).
+    pub fn encrypt(&self, recipient: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, ResponseStatus> {
+        if !self.is_ecies_capable() {
+            return Err(ResponseStatus::PsaErrorNotSupported);
+        }
+        ecies::encrypt(recipient, plaintext)
+    }
+
+    /// PSA `psa_asymmetric_decrypt` entry point for this slot: `z` is the ECDH
+    /// shared secret the ATECC has already computed in hardware between this
+    /// slot's private key and the ephemeral public key at the head of `wire`.
+    pub fn decrypt(&self, z: &[u8], wire: &[u8]) -> Result<Vec<u8>, ResponseStatus> {
+        if !self.is_ecies_capable() {
+            return Err(ResponseStatus::PsaErrorNotSupported);
+        }
+        ecies::decrypt(z, wire)
+    }
+
+    fn is_ecies_capable(&self) -> bool {
+        self.config.key_type == rust_cryptoauthlib::KeyType::P256EccKey
+            && self.config.ecc_key_attr.ecdh_operation
+            && self.config.ecc_key_attr.ecdh_secret_out
+    }
+
+    /// PSA `psa_export_public_key` entry point for this slot: DER-encode the
+    /// raw 64-byte point the ATECC returns as a `SubjectPublicKeyInfo` (see
+    /// the `spki` module below). Gated the same way `is_usage_flags_ok`
+    /// already gates `export`/`copy`: only a P256 slot with `pub_info` set
+    /// can hand its public key back out.
+    pub fn export_public_key(&self, point: &[u8; 64]) -> Result<Vec<u8>, ResponseStatus> {
+        if self.config.key_type != rust_cryptoauthlib::KeyType::P256EccKey || !self.config.pub_info
+        {
+            return Err(ResponseStatus::PsaErrorNotSupported);
+        }
+        Ok(spki::encode(point))
+    }
+
+    /// PSA `psa_import_key` entry point for a `SubjectPublicKeyInfo` blob:
+    /// validate it describes `id-ecPublicKey` over `secp256r1` and unwrap it
+    /// to the raw point written into this slot.
+    pub fn import_public_key(&self, der: &[u8]) -> Result<[u8; 64], ResponseStatus> {
+        if self.config.key_type != rust_cryptoauthlib::KeyType::P256EccKey {
+            return Err(ResponseStatus::PsaErrorNotSupported);
+        }
+        spki::decode(der)
+    }
+
+    /// PSA `KeyAgreement::WithKeyDerivation` entry point for this slot: `z` is
+    /// the ECDH shared secret the ATECC has already computed in hardware
+    /// using this slot's private key, and this runs HKDF-SHA256 over it (see
+    /// the `key_derivation` module below) to produce `output_len` bytes of
+    /// derived key material.
+    pub fn derive(
+        &self,
+        salt: Option<&[u8]>,
+        z: &[u8],
+        info: &[u8],
+        output_len: usize,
+    ) -> Result<Vec<u8>, ResponseStatus> {
+        if self.config.key_type != rust_cryptoauthlib::KeyType::P256EccKey {
+            return Err(ResponseStatus::PsaErrorNotSupported);
+        }
+        key_derivation::hkdf_sha256(salt, z, info, output_len)
+    }
+}
+
+/// Allocates ATECC hardware slots to PSA keys: given the attributes of a key
+/// being created, finds the most constrained `Free` slot that is compatible
+/// with it, replacing first-come occupation with deterministic,
+/// attribute-aware placement. This is the key-create/destroy entry point
+/// that replaces the old binary `reference_check_and_set` guard.
+#[derive(Debug, Default)]
+pub struct AteccKeySlots {
+    slots: Vec<AteccKeySlot>,
+}
+
+impl AteccKeySlots {
+    pub fn new(slots: Vec<AteccKeySlot>) -> Self {
+        AteccKeySlots { slots }
+    }
+
+    /// Find the most constrained `Free` slot compatible with `key_attr`,
+    /// mark it `Busy` and give it its first reference. Returns the chosen
+    /// slot's index, used by callers as the ATECC hardware slot id.
+    pub fn allocate(&mut self, key_attr: &Attributes) -> Result<usize, ResponseStatus> {
+        let chosen = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_free() && slot.key_attr_vs_config(key_attr).is_ok())
+            .min_by_key(|(_, slot)| slot.constraint_score())
+            .map(|(index, _)| index)
+            .ok_or(ResponseStatus::PsaErrorInsufficientStorage)?;
+
+        // Reference first: if this ever errors (ref_count overflow) the slot is left
+        // untouched and `Free`, instead of `Busy` with a stale ref_count.
+        let slot = &mut self.slots[chosen];
+        slot.add_reference()?;
+        if let Err(err) = slot.set_slot_status(KeySlotStatus::Busy) {
+            slot.release()?;
+            return Err(err);
+        }
+        Ok(chosen)
+    }
+
+    /// Add a reference to an already-allocated slot, for a key that
+    /// legitimately shares it with others (e.g. a read-only public key).
+    pub fn share(&mut self, index: usize) -> Result<(), ResponseStatus> {
+        self.slot_mut(index)?.add_reference()
+    }
+
+    /// Drop a reference to `index`, releasing the slot back to `Free` once
+    /// no key triple references it any longer.
+    pub fn release(&mut self, index: usize) -> Result<(), ResponseStatus> {
+        self.slot_mut(index)?.release()
+    }
+
+    fn slot_mut(&mut self, index: usize) -> Result<&mut AteccKeySlot, ResponseStatus> {
+        self.slots
+            .get_mut(index)
+            .ok_or(ResponseStatus::PsaErrorInvalidHandle)
+    }
+}
+
+/// ECIES hybrid encryption, used to offer PSA asymmetric encrypt/decrypt on
+/// P256 slots even though the ATECC hardware only natively supports ECDH.
+///
+/// Wire format: `ephemeral public key (65 bytes, X9.62 uncompressed) || ciphertext || tag (16 bytes)`.
+/// Encryption generates a fresh ephemeral P256 key pair, so reusing the
+/// all-zero AES-GCM nonce below is safe: the derived AES key is never used
+/// for more than the one message it was derived for.
+pub(crate) mod ecies {
+    use super::*;
+    use aes_gcm::aead::{Aead as _, Payload};
+    use aes_gcm::{Aes128Gcm, KeyInit, Nonce};
+    use hkdf::Hkdf;
+    use p256::ecdh::EphemeralSecret;
+    use p256::{EncodedPoint, PublicKey};
+    use rand_core::OsRng;
+    use sha2::Sha256;
+
+    const PUBLIC_KEY_LEN: usize = 65;
+    const TAG_LEN: usize = 16;
+    const AES_KEY_LEN: usize = 16;
+    const NONCE: [u8; 12] = [0u8; 12];
+
+    /// Derive the 16-byte AES-128 key from the raw ECDH shared secret `z`
+    /// using HKDF-SHA256 with no salt or info.
+    fn derive_key(z: &[u8]) -> [u8; AES_KEY_LEN] {
+        let hkdf = Hkdf::<Sha256>::new(None, z);
+        let mut key = [0u8; AES_KEY_LEN];
+        hkdf.expand(&[], &mut key)
+            .expect("AES-128 key length is far below HKDF-SHA256's output limit");
+        key
+    }
+
+    /// Encrypt `plaintext` for the P256 public key `recipient` (X9.62
+    /// uncompressed, 65 bytes), returning the ECIES wire format described
+    /// above.
+    pub(crate) fn encrypt(recipient: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, ResponseStatus> {
+        let recipient_point = EncodedPoint::from_bytes(recipient)
+            .map_err(|_| ResponseStatus::PsaErrorInvalidArgument)?;
+        let recipient_key = PublicKey::from_sec1_bytes(recipient_point.as_bytes())
+            .map_err(|_| ResponseStatus::PsaErrorInvalidArgument)?;
+
+        let ephemeral_secret = EphemeralSecret::random(&mut OsRng);
+        let ephemeral_public = EncodedPoint::from(ephemeral_secret.public_key());
+        let shared_secret = ephemeral_secret.diffie_hellman(&recipient_key);
+        let key = derive_key(shared_secret.raw_secret_bytes());
+
+        let cipher =
+            Aes128Gcm::new_from_slice(&key).map_err(|_| ResponseStatus::PsaErrorGenericError)?;
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&NONCE),
+                Payload {
+                    msg: plaintext,
+                    aad: &[],
+                },
+            )
+            .map_err(|_| ResponseStatus::PsaErrorGenericError)?;
+
+        let mut wire = Vec::with_capacity(PUBLIC_KEY_LEN + ciphertext.len());
+        wire.extend_from_slice(ephemeral_public.as_bytes());
+        wire.extend_from_slice(&ciphertext);
+        Ok(wire)
+    }
+
+    /// Decrypt an ECIES wire-format blob, given the shared secret `z` already
+    /// produced by the ATECC performing ECDH between the slot's private key
+    /// and the ephemeral public key found at the head of `wire`.
+    pub(crate) fn decrypt(z: &[u8], wire: &[u8]) -> Result<Vec<u8>, ResponseStatus> {
+        if wire.len() < PUBLIC_KEY_LEN + TAG_LEN {
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        let key = derive_key(z);
+        let cipher =
+            Aes128Gcm::new_from_slice(&key).map_err(|_| ResponseStatus::PsaErrorGenericError)?;
+        cipher
+            .decrypt(
+                Nonce::from_slice(&NONCE),
+                Payload {
+                    msg: &wire[PUBLIC_KEY_LEN..],
+                    aad: &[],
+                },
+            )
+            .map_err(|_| ResponseStatus::PsaErrorInvalidSignature)
+    }
+
+    /// Extract the 65-byte X9.62 uncompressed ephemeral public key from the
+    /// head of an ECIES wire-format blob, so the caller can hand it to the
+    /// ATECC for the hardware ECDH step.
+    pub(crate) fn ephemeral_public_key(wire: &[u8]) -> Result<&[u8], ResponseStatus> {
+        wire.get(..PUBLIC_KEY_LEN)
+            .ok_or(ResponseStatus::PsaErrorInvalidArgument)
+    }
+}
+
+/// DER encoding of a raw P256 point as a `SubjectPublicKeyInfo`, for the
+/// export path gated by `is_usage_flags_ok`'s `export`/`copy` handling: the
+/// ATECC only ever hands back the raw 64-byte uncompressed point, but
+/// PSA/clients expect `SEQUENCE { SEQUENCE { OID id-ecPublicKey, OID
+/// secp256r1 }, BIT STRING { 0x00, 0x04 || X || Y } }`.
+pub(crate) mod spki {
+    use super::*;
+
+    const OID_EC_PUBLIC_KEY: [u8; 7] = [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+    const OID_SECP256R1: [u8; 8] = [0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+    const TAG_SEQUENCE: u8 = 0x30;
+    const TAG_OID: u8 = 0x06;
+    const TAG_BIT_STRING: u8 = 0x03;
+
+    /// Append a DER length field for `len`: short form below 0x80, long form
+    /// (a leading 0x80|num_bytes octet followed by the big-endian length)
+    /// otherwise.
+    fn encode_length(len: usize, out: &mut Vec<u8>) {
+        if len < 0x80 {
+            out.push(len as u8);
+            return;
+        }
+        let bytes = len.to_be_bytes();
+        let first_nonzero = bytes
+            .iter()
+            .position(|&b| b != 0)
+            .unwrap_or(bytes.len() - 1);
+        let significant = &bytes[first_nonzero..];
+        out.push(0x80 | significant.len() as u8);
+        out.extend_from_slice(significant);
+    }
+
+    fn encode_tlv(tag: u8, content: &[u8], out: &mut Vec<u8>) {
+        out.push(tag);
+        encode_length(content.len(), out);
+        out.extend_from_slice(content);
+    }
+
+    /// Encode a raw 64-byte uncompressed P256 point (X || Y) as a DER
+    /// `SubjectPublicKeyInfo`.
+    pub(crate) fn encode(point: &[u8; 64]) -> Vec<u8> {
+        let mut algorithm = Vec::new();
+        encode_tlv(TAG_OID, &OID_EC_PUBLIC_KEY, &mut algorithm);
+        encode_tlv(TAG_OID, &OID_SECP256R1, &mut algorithm);
+        let mut algorithm_id = Vec::new();
+        encode_tlv(TAG_SEQUENCE, &algorithm, &mut algorithm_id);
+
+        let mut bit_string_content = Vec::with_capacity(2 + point.len());
+        bit_string_content.push(0x00); // no unused bits
+        bit_string_content.push(0x04); // uncompressed point indicator
+        bit_string_content.extend_from_slice(point);
+        let mut bit_string = Vec::new();
+        encode_tlv(TAG_BIT_STRING, &bit_string_content, &mut bit_string);
+
+        let mut spki_content = algorithm_id;
+        spki_content.extend_from_slice(&bit_string);
+        let mut spki = Vec::new();
+        encode_tlv(TAG_SEQUENCE, &spki_content, &mut spki);
+        spki
+    }
+
+    /// Read one DER TLV off the front of `der`, returning its tag, content
+    /// and the remaining bytes.
+    fn read_tlv(der: &[u8]) -> Result<(u8, &[u8], &[u8]), ResponseStatus> {
+        let (&tag, rest) = der
+            .split_first()
+            .ok_or(ResponseStatus::PsaErrorInvalidArgument)?;
+        let (&first_len, rest) = rest
+            .split_first()
+            .ok_or(ResponseStatus::PsaErrorInvalidArgument)?;
+        let (len, rest) = if first_len < 0x80 {
+            (first_len as usize, rest)
+        } else {
+            let num_bytes = (first_len & 0x7f) as usize;
+            if rest.len() < num_bytes {
+                return Err(ResponseStatus::PsaErrorInvalidArgument);
+            }
+            let (len_bytes, rest) = rest.split_at(num_bytes);
+            let len = len_bytes
+                .iter()
+                .fold(0usize, |acc, &b| (acc << 8) | b as usize);
+            (len, rest)
+        };
+        if rest.len() < len {
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        let (content, rest) = rest.split_at(len);
+        Ok((tag, content, rest))
+    }
+
+    /// Parse a DER `SubjectPublicKeyInfo`, checking it carries `id-ecPublicKey`
+    /// over `secp256r1` (rejecting anything else), and return the raw 64-byte
+    /// uncompressed point ready to be written to a slot.
+    pub(crate) fn decode(der: &[u8]) -> Result<[u8; 64], ResponseStatus> {
+        let (tag, spki_content, trailing) = read_tlv(der)?;
+        if tag != TAG_SEQUENCE || !trailing.is_empty() {
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+
+        let (tag, algorithm_content, rest) = read_tlv(spki_content)?;
+        if tag != TAG_SEQUENCE {
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        let (tag, oid, algorithm_rest) = read_tlv(algorithm_content)?;
+        if tag != TAG_OID || oid != OID_EC_PUBLIC_KEY {
+            return Err(ResponseStatus::PsaErrorNotSupported);
+        }
+        let (tag, oid, algorithm_rest) = read_tlv(algorithm_rest)?;
+        if tag != TAG_OID || oid != OID_SECP256R1 || !algorithm_rest.is_empty() {
+            return Err(ResponseStatus::PsaErrorNotSupported);
+        }
+
+        let (tag, bit_string_content, _) = read_tlv(rest)?;
+        if tag != TAG_BIT_STRING
+            || bit_string_content.len() != 66
+            || bit_string_content[0] != 0x00
+            || bit_string_content[1] != 0x04
+        {
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+
+        let mut point = [0u8; 64];
+        point.copy_from_slice(&bit_string_content[2..]);
+        Ok(point)
+    }
+}
+
+/// HKDF-SHA256 (RFC 5869) over an ECDH shared secret, completing the PSA
+/// `KeyAgreement::WithKeyDerivation` path: the ATECC computes the raw ECDH
+/// shared secret `z` in hardware, and this module turns it into the derived
+/// key material the client asked for.
+pub(crate) mod key_derivation {
+    use super::*;
+    use hkdf::Hkdf;
+    use sha2::Sha256;
+
+    /// HKDF-SHA256's hard limit: 255 blocks of the 32-byte hash output.
+    pub(crate) const MAX_OUTPUT_LEN: usize = 255 * 32;
+
+    /// Run HKDF-SHA256 Extract-then-Expand over the ECDH shared secret `z`,
+    /// producing `output_len` bytes of derived key material. `salt` defaults
+    /// to a zero block of hash length when absent, per RFC 5869.
+    pub(crate) fn hkdf_sha256(
+        salt: Option<&[u8]>,
+        z: &[u8],
+        info: &[u8],
+        output_len: usize,
+    ) -> Result<Vec<u8>, ResponseStatus> {
+        if output_len > MAX_OUTPUT_LEN {
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        let hkdf = Hkdf::<Sha256>::new(salt, z);
+        let mut output = vec![0u8; output_len];
+        hkdf.expand(info, &mut output)
+            .map_err(|_| ResponseStatus::PsaErrorInvalidArgument)?;
+        Ok(output)
+    }
 }
 
 #[cfg(test)]
@@ -323,6 +755,15 @@ mod tests {
         // Type::RawData => OK
         attributes.key_type = Type::RawData;
         assert_eq!(key_slot.is_key_type_ok(&attributes), true);
+
+        // KeyType::ShaOrText, no_mac == false
+        // Type::Derive => OK
+        key_slot.config.no_mac = false;
+        attributes.key_type = Type::Derive;
+        assert_eq!(key_slot.is_key_type_ok(&attributes), true);
+        // no_mac == true => NOK
+        key_slot.config.no_mac = true;
+        assert_eq!(key_slot.is_key_type_ok(&attributes), false);
     }
 
     #[test]
@@ -501,6 +942,24 @@ mod tests {
         // && RawKeyAgreement::Ecdh => OK
         attributes.policy.permitted_algorithms = KeyAgreement::Raw(RawKeyAgreement::Ecdh).into();
         assert_eq!(key_slot.is_permitted_algorithms_ok(&attributes), true);
+        // && WithKeyDerivation{Ecdh, Hkdf(Sha256)} => OK
+        attributes.policy.permitted_algorithms = KeyAgreement::WithKeyDerivation {
+            ka_alg: RawKeyAgreement::Ecdh,
+            kdf_alg: KeyDerivationFunction::Hkdf {
+                hash_alg: Hash::Sha256,
+            },
+        }
+        .into();
+        assert_eq!(key_slot.is_permitted_algorithms_ok(&attributes), true);
+        // && WithKeyDerivation{Ecdh, Tls12Prf(Sha256)} => NOK (only HKDF-SHA256 is implemented)
+        attributes.policy.permitted_algorithms = KeyAgreement::WithKeyDerivation {
+            ka_alg: RawKeyAgreement::Ecdh,
+            kdf_alg: KeyDerivationFunction::Tls12Prf {
+                hash_alg: Hash::Sha256,
+            },
+        }
+        .into();
+        assert_eq!(key_slot.is_permitted_algorithms_ok(&attributes), false);
 
         // KeyType::Aes
         // && Aead::AeadWithDefaultLengthTag => OK
@@ -511,5 +970,401 @@ mod tests {
         // && Cipher(Cipher::CbcPkcs7) => OK
         attributes.policy.permitted_algorithms = Algorithm::Cipher(Cipher::CbcPkcs7);
         assert_eq!(key_slot.is_permitted_algorithms_ok(&attributes), true);
+
+        // KeyType::P256EccKey, full ECDH capability
+        // && AsymmetricEncryption(RsaOaep | RsaPkcs1v15Crypt) => NOK: PSA has no
+        // ECC/ECIES identifier, so the ATECC (no RSA engine) must reject both,
+        // even though the slot is otherwise ECIES-capable (see `AteccKeySlot::encrypt`).
+        key_slot.config.key_type = rust_cryptoauthlib::KeyType::P256EccKey;
+        key_slot.config.ecc_key_attr.ecdh_operation = true;
+        key_slot.config.ecc_key_attr.ecdh_secret_out = true;
+        attributes.policy.permitted_algorithms =
+            Algorithm::AsymmetricEncryption(AsymmetricEncryption::RsaOaep {
+                hash_alg: Hash::Sha256,
+            });
+        assert_eq!(key_slot.is_permitted_algorithms_ok(&attributes), false);
+        attributes.policy.permitted_algorithms =
+            Algorithm::AsymmetricEncryption(AsymmetricEncryption::RsaPkcs1v15Crypt);
+        assert_eq!(key_slot.is_permitted_algorithms_ok(&attributes), false);
+    }
+
+    #[test]
+    fn test_ecies_round_trip() {
+        use p256::ecdh::EphemeralSecret;
+        use p256::EncodedPoint;
+        use rand_core::OsRng;
+
+        let recipient_secret = EphemeralSecret::random(&mut OsRng);
+        let recipient_public = EncodedPoint::from(recipient_secret.public_key());
+
+        let plaintext = b"session key material";
+        let wire = ecies::encrypt(recipient_public.as_bytes(), plaintext).unwrap();
+
+        let ephemeral_public = ecies::ephemeral_public_key(&wire).unwrap();
+        let ephemeral_public = p256::PublicKey::from_sec1_bytes(ephemeral_public).unwrap();
+        let shared_secret = recipient_secret.diffie_hellman(&ephemeral_public);
+
+        let decrypted = ecies::decrypt(shared_secret.raw_secret_bytes(), &wire).unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn test_ecies_decrypt_rejects_truncated_wire() {
+        assert_eq!(
+            ecies::decrypt(&[0u8; 32], &[0u8; 10]),
+            Err(ResponseStatus::PsaErrorInvalidArgument)
+        );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_slot_encrypt_rejects_non_ecies_capable_slot() {
+        let slot = AteccKeySlot {
+            ref_count: 0,
+            status: KeySlotStatus::Free,
+            config: rust_cryptoauthlib::SlotConfig::default(),
+        };
+        assert_eq!(
+            slot.encrypt(&[0u8; 65], b"plaintext"),
+            Err(ResponseStatus::PsaErrorNotSupported)
+        );
+        assert_eq!(
+            slot.decrypt(&[0u8; 32], &[0u8; 81]),
+            Err(ResponseStatus::PsaErrorNotSupported)
+        );
+    }
+
+    #[test]
+    fn test_spki_round_trip() {
+        let mut point = [0u8; 64];
+        for (i, byte) in point.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let der = spki::encode(&point);
+        // SEQUENCE(0x30) + short-form length + AlgorithmIdentifier(21 bytes) + BIT STRING(68 bytes)
+        assert_eq!(der[0], 0x30);
+        assert_eq!(der[1], 0x59);
+        assert_eq!(spki::decode(&der).unwrap(), point);
+    }
+
+    #[test]
+    fn test_spki_decode_rejects_wrong_oid() {
+        let mut point = [0u8; 64];
+        let der = spki::encode(&point);
+        point[0] = 0xff;
+        let mut tampered = der.clone();
+        // Flip a byte inside the first OID so it no longer reads id-ecPublicKey.
+        let oid_offset = 6;
+        tampered[oid_offset] ^= 0xff;
+        assert_eq!(
+            spki::decode(&tampered),
+            Err(ResponseStatus::PsaErrorNotSupported)
+        );
+    }
+
+    #[test]
+    fn test_spki_decode_rejects_truncated_der() {
+        assert_eq!(
+            spki::decode(&[0x30, 0x05, 0x00]),
+            Err(ResponseStatus::PsaErrorInvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_slot_export_public_key_requires_pub_info() {
+        let mut slot = AteccKeySlot {
+            ref_count: 1,
+            status: KeySlotStatus::Busy,
+            config: SlotConfig {
+                write_config: rust_cryptoauthlib::WriteConfig::Always,
+                key_type: rust_cryptoauthlib::KeyType::P256EccKey,
+                read_key: ReadKey {
+                    encrypt_read: false,
+                    slot_number: 0,
+                },
+                ecc_key_attr: EccKeyAttr {
+                    is_private: true,
+                    ext_sign: false,
+                    int_sign: false,
+                    ecdh_operation: false,
+                    ecdh_secret_out: false,
+                },
+                x509id: 0,
+                auth_key: 0,
+                write_key: 0,
+                is_secret: false,
+                limited_use: false,
+                no_mac: true,
+                persistent_disable: false,
+                req_auth: false,
+                req_random: false,
+                lockable: false,
+                pub_info: false,
+            },
+        };
+        let point = [0u8; 64];
+        assert_eq!(
+            slot.export_public_key(&point),
+            Err(ResponseStatus::PsaErrorNotSupported)
+        );
+
+        slot.config.pub_info = true;
+        let der = slot.export_public_key(&point).unwrap();
+        assert_eq!(slot.import_public_key(&der).unwrap(), point);
+    }
+
+    #[test]
+    fn test_hkdf_sha256_derives_requested_length() {
+        let z = [0x42u8; 32];
+        let derived = key_derivation::hkdf_sha256(None, &z, b"session", 48).unwrap();
+        assert_eq!(derived.len(), 48);
+        // Deterministic for the same inputs.
+        assert_eq!(
+            derived,
+            key_derivation::hkdf_sha256(None, &z, b"session", 48).unwrap()
+        );
+        // A different salt changes the output.
+        assert_ne!(
+            derived,
+            key_derivation::hkdf_sha256(Some(b"salt"), &z, b"session", 48).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_hkdf_sha256_rejects_output_past_rfc5869_limit() {
+        assert_eq!(
+            key_derivation::hkdf_sha256(None, &[0u8; 32], b"", key_derivation::MAX_OUTPUT_LEN + 1),
+            Err(ResponseStatus::PsaErrorInvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_slot_derive_rejects_non_p256_slot() {
+        let slot = AteccKeySlot {
+            ref_count: 1,
+            status: KeySlotStatus::Busy,
+            config: SlotConfig {
+                key_type: rust_cryptoauthlib::KeyType::Aes,
+                ..rust_cryptoauthlib::SlotConfig::default()
+            },
+        };
+        assert_eq!(
+            slot.derive(None, &[0u8; 32], b"info", 32),
+            Err(ResponseStatus::PsaErrorNotSupported)
+        );
+    }
+
+    fn raw_data_attributes() -> Attributes {
+        Attributes {
+            lifetime: Lifetime::Persistent,
+            key_type: Type::RawData,
+            bits: 256,
+            policy: Policy {
+                usage_flags: UsageFlags {
+                    sign_hash: false,
+                    verify_hash: false,
+                    sign_message: false,
+                    verify_message: false,
+                    export: false,
+                    encrypt: false,
+                    decrypt: false,
+                    cache: false,
+                    copy: false,
+                    derive: false,
+                },
+                permitted_algorithms: Hash::Sha256.into(),
+            },
+        }
+    }
+
+    /// ECDH-only P256 ECC key-agreement attributes: both a constrained
+    /// (ECDH-only) and a permissive (sign+ECDH+export) slot are compatible
+    /// with these, since `key_attr_vs_config` only checks `key_type` for
+    /// `KeyAgreement::Raw(Ecdh)`. Used to show the allocator picks the
+    /// constrained one on capability breadth, not on `key_type` alone.
+    fn ecdh_key_agreement_attributes() -> Attributes {
+        Attributes {
+            lifetime: Lifetime::Persistent,
+            key_type: Type::EccKeyPair {
+                curve_family: EccFamily::SecpR1,
+            },
+            bits: 256,
+            policy: Policy {
+                usage_flags: UsageFlags {
+                    sign_hash: false,
+                    verify_hash: false,
+                    sign_message: false,
+                    verify_message: false,
+                    export: false,
+                    encrypt: false,
+                    decrypt: false,
+                    cache: false,
+                    copy: false,
+                    derive: false,
+                },
+                permitted_algorithms: KeyAgreement::Raw(RawKeyAgreement::Ecdh).into(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_allocate_prefers_constrained_slot_over_permissive_one() {
+        let ecdh_only = SlotConfig {
+            write_config: rust_cryptoauthlib::WriteConfig::Always,
+            key_type: rust_cryptoauthlib::KeyType::P256EccKey,
+            read_key: ReadKey {
+                encrypt_read: false,
+                slot_number: 0,
+            },
+            ecc_key_attr: EccKeyAttr {
+                is_private: true,
+                ext_sign: false,
+                int_sign: false,
+                ecdh_operation: true,
+                ecdh_secret_out: false,
+            },
+            x509id: 0,
+            auth_key: 0,
+            write_key: 0,
+            is_secret: true,
+            limited_use: false,
+            no_mac: true,
+            persistent_disable: false,
+            req_auth: false,
+            req_random: false,
+            lockable: false,
+            pub_info: false,
+        };
+        // Same key_type as `ecdh_only`, but also signs, exports and MACs: every
+        // capability `ecdh_only` has plus more, so it must score strictly higher.
+        let sign_ecdh_export = SlotConfig {
+            ecc_key_attr: EccKeyAttr {
+                ext_sign: true,
+                int_sign: true,
+                ecdh_secret_out: true,
+                ..ecdh_only.ecc_key_attr
+            },
+            pub_info: true,
+            no_mac: false,
+            ..ecdh_only
+        };
+
+        // Put the permissive slot first so a first-come allocator (the bug under
+        // test) would pick it, and the constrained one would only be picked by
+        // genuine scoring.
+        let mut slots = AteccKeySlots::new(vec![
+            AteccKeySlot {
+                ref_count: 0,
+                status: KeySlotStatus::Free,
+                config: sign_ecdh_export,
+            },
+            AteccKeySlot {
+                ref_count: 0,
+                status: KeySlotStatus::Free,
+                config: ecdh_only,
+            },
+        ]);
+        let index = slots.allocate(&ecdh_key_agreement_attributes()).unwrap();
+        assert_eq!(
+            index, 1,
+            "the ECDH-only, more constrained slot must be chosen"
+        );
+        assert_eq!(slots.slots[1].status, KeySlotStatus::Busy);
+        assert!(
+            slots.slots[0].is_free(),
+            "the more permissive slot must stay free"
+        );
+    }
+
+    #[test]
+    fn test_allocate_fails_when_no_compatible_free_slot() {
+        let aes_config = SlotConfig {
+            write_config: rust_cryptoauthlib::WriteConfig::Always,
+            key_type: rust_cryptoauthlib::KeyType::Aes,
+            read_key: ReadKey {
+                encrypt_read: false,
+                slot_number: 0,
+            },
+            ecc_key_attr: EccKeyAttr {
+                is_private: false,
+                ext_sign: false,
+                int_sign: false,
+                ecdh_operation: false,
+                ecdh_secret_out: false,
+            },
+            x509id: 0,
+            auth_key: 0,
+            write_key: 0,
+            is_secret: false,
+            limited_use: false,
+            no_mac: false,
+            persistent_disable: false,
+            req_auth: false,
+            req_random: false,
+            lockable: false,
+            pub_info: false,
+        };
+        let mut slots = AteccKeySlots::new(vec![AteccKeySlot {
+            ref_count: 0,
+            status: KeySlotStatus::Free,
+            config: aes_config,
+        }]);
+        assert_eq!(
+            slots.allocate(&raw_data_attributes()),
+            Err(ResponseStatus::PsaErrorInsufficientStorage)
+        );
+    }
+
+    #[test]
+    fn test_share_and_release_ref_counting() {
+        let config = SlotConfig {
+            write_config: rust_cryptoauthlib::WriteConfig::Always,
+            key_type: rust_cryptoauthlib::KeyType::ShaOrText,
+            read_key: ReadKey {
+                encrypt_read: false,
+                slot_number: 0,
+            },
+            ecc_key_attr: EccKeyAttr {
+                is_private: false,
+                ext_sign: false,
+                int_sign: false,
+                ecdh_operation: false,
+                ecdh_secret_out: false,
+            },
+            x509id: 0,
+            auth_key: 0,
+            write_key: 0,
+            is_secret: false,
+            limited_use: false,
+            no_mac: false,
+            persistent_disable: false,
+            req_auth: false,
+            req_random: false,
+            lockable: false,
+            pub_info: false,
+        };
+        let mut slots = AteccKeySlots::new(vec![AteccKeySlot {
+            ref_count: 0,
+            status: KeySlotStatus::Free,
+            config,
+        }]);
+        let index = slots.allocate(&raw_data_attributes()).unwrap();
+        slots.share(index).unwrap();
+        assert_eq!(slots.slots[index].ref_count, 2);
+
+        slots.release(index).unwrap();
+        assert!(!slots.slots[index].is_free(), "still referenced once");
+        slots.release(index).unwrap();
+        assert!(slots.slots[index].is_free(), "released at zero refs");
+    }
+
+    #[test]
+    fn test_release_never_frees_a_locked_slot() {
+        let mut slot = AteccKeySlot {
+            ref_count: 1,
+            status: KeySlotStatus::Locked,
+            config: SlotConfig::default(),
+        };
+        slot.release().unwrap();
+        assert_eq!(slot.status, KeySlotStatus::Locked);
+    }
+}